@@ -10,16 +10,25 @@ use std::str::FromStr;
 use std::time::Duration;
 use warp::{http::HeaderMap, http::Response, Filter};
 
-use sqlx::postgres::PgPoolOptions;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
 use sqlx::{Pool, Postgres, Row};
 
+use log::{error, info};
 use opentelemetry::global::shutdown_tracer_provider;
+use opentelemetry::logs::LogError;
+use opentelemetry::metrics::{Counter, Histogram, MetricsError};
 use opentelemetry::sdk::Resource;
-use opentelemetry::trace::Span;
+use opentelemetry::trace::TraceContextExt;
 use opentelemetry::trace::TraceError;
 use opentelemetry::trace::Tracer;
-use opentelemetry::{global, sdk::trace as sdktrace};
+use opentelemetry::{
+    global, sdk::logs as sdklogs, sdk::metrics as sdkmetrics, sdk::trace as sdktrace, KeyValue,
+};
+use opentelemetry_appender_log::OpenTelemetryLogBridge;
 use opentelemetry_otlp::WithExportConfig;
+use prometheus::{Encoder, TextEncoder};
+use std::sync::OnceLock;
+use std::time::Instant;
 
 const HTML: &str = r###"
 <!DOCTYPE html>
@@ -53,9 +62,91 @@ fn init_tracer() -> Result<sdktrace::Tracer, TraceError> {
         .install_batch(opentelemetry::runtime::Tokio)
 }
 
+// builds a single meter provider with two readers sharing the same
+// instruments: a periodic OTLP push reader for the collector, and a
+// Prometheus pull reader backed by `registry` for the `/metrics` route
+fn init_meter(registry: prometheus::Registry) -> Result<sdkmetrics::MeterProvider, MetricsError> {
+    let otlp_exporter = opentelemetry_otlp::MetricsExporterBuilder::from(
+        opentelemetry_otlp::new_exporter().tonic().with_env(),
+    )
+    .build_metrics_exporter(
+        Box::new(sdkmetrics::reader::DefaultTemporalitySelector::new()),
+        Box::new(sdkmetrics::reader::DefaultAggregationSelector::new()),
+    )?;
+    let otlp_reader =
+        sdkmetrics::PeriodicReader::builder(otlp_exporter, opentelemetry::runtime::Tokio).build();
+
+    let prometheus_reader = opentelemetry_prometheus::exporter()
+        .with_registry(registry)
+        .build()?;
+
+    let provider = sdkmetrics::MeterProvider::builder()
+        .with_reader(otlp_reader)
+        .with_reader(prometheus_reader)
+        .with_resource(Resource::default())
+        .build();
+
+    global::set_meter_provider(provider.clone());
+    Ok(provider)
+}
+
+// builds the OTLP logs pipeline and bridges it onto the `log` facade, so
+// `log::info!`/`log::error!` calls made from within an active span carry
+// that span's trace/span IDs for correlation in the backend
+fn init_logger() -> Result<sdklogs::LoggerProvider, LogError> {
+    let provider = opentelemetry_otlp::new_pipeline()
+        .logging()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_env())
+        .with_log_config(sdklogs::config().with_resource(Resource::default()))
+        .install_batch(opentelemetry::runtime::Tokio)?;
+
+    let otel_log_bridge = OpenTelemetryLogBridge::new(&provider);
+    log::set_boxed_logger(Box::new(otel_log_bridge)).map_err(|e| LogError::Other(Box::new(e)))?;
+    log::set_max_level(log::LevelFilter::Info);
+
+    Ok(provider)
+}
+
+static REQUEST_COUNTER: OnceLock<Counter<u64>> = OnceLock::new();
+static REQUEST_LATENCY: OnceLock<Histogram<f64>> = OnceLock::new();
+
+fn request_counter() -> &'static Counter<u64> {
+    REQUEST_COUNTER.get_or_init(|| {
+        global::meter("global_meter")
+            .u64_counter("http.server.requests")
+            .with_description("number of HTTP requests received")
+            .init()
+    })
+}
+
+fn request_latency() -> &'static Histogram<f64> {
+    REQUEST_LATENCY.get_or_init(|| {
+        global::meter("global_meter")
+            .f64_histogram("http.server.duration")
+            .with_description("HTTP request latency in seconds")
+            .with_unit(opentelemetry::metrics::Unit::new("s"))
+            .init()
+    })
+}
+
+// records one request: increments the counter and observes the latency
+// histogram, both tagged by route and HTTP status. The instruments are
+// built once and reused, rather than re-created on every request.
+fn record_request(route: &'static str, status: u16, start: Instant) {
+    let labels = [
+        KeyValue::new("route", route),
+        KeyValue::new("status", status as i64),
+    ];
+    request_counter().add(1, &labels);
+    request_latency().record(start.elapsed().as_secs_f64(), &labels);
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
     let _ = init_tracer().unwrap();
+    let registry = prometheus::Registry::new();
+    let meter_provider = init_meter(registry.clone()).unwrap();
+    let logger_provider = init_logger().unwrap();
     let matches = Command::new("demo")
         .version(format!("{} {}", env!("CARGO_PKG_VERSION"), GIT_COMMIT_HASH))
         .arg(
@@ -66,10 +157,66 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
                 .help("listening port")
                 .value_parser(clap::value_parser!(u16)),
         )
+        .arg(
+            Arg::new("db-user")
+                .long("db-user")
+                .env("DB_USER")
+                .default_value("postgres")
+                .help("database user"),
+        )
+        .arg(
+            Arg::new("db-pass")
+                .long("db-pass")
+                .env("DB_PASS")
+                .default_value("")
+                .help("database password"),
+        )
+        .arg(
+            Arg::new("db-host")
+                .long("db-host")
+                .env("DB_HOST")
+                .default_value("localhost")
+                .help("database host; when --db-hostaddr is set this is only kept as the connection's application_name"),
+        )
+        .arg(
+            Arg::new("db-port")
+                .long("db-port")
+                .env("DB_PORT")
+                .default_value("5432")
+                .help("database port")
+                .value_parser(clap::value_parser!(u16)),
+        )
+        .arg(
+            Arg::new("db-name")
+                .long("db-name")
+                .env("DB_NAME")
+                .default_value("demo")
+                .help("database name"),
+        )
+        .arg(
+            Arg::new("db-hostaddr")
+                .long("db-hostaddr")
+                .env("DB_HOSTADDR")
+                .help(
+                    "numeric IP of the database, skips DNS resolution of --db-host; \
+                     if TLS is enabled, certificate/SNI verification then runs against \
+                     this IP rather than --db-host, and will fail against a hostname cert",
+                ),
+        )
         .get_matches();
 
     let port: u16 = *matches.get_one("port").unwrap();
 
+    let db_user = matches.get_one::<String>("db-user").unwrap();
+    let db_pass = matches.get_one::<String>("db-pass").unwrap();
+    let db_host = matches.get_one::<String>("db-host").unwrap();
+    let db_port: u16 = *matches.get_one("db-port").unwrap();
+    let db_name = matches.get_one::<String>("db-name").unwrap();
+    let db_hostaddr = matches
+        .get_one::<String>("db-hostaddr")
+        .map(|s| IpAddr::from_str(s))
+        .transpose()?;
+
     let now = Utc::now();
     println!(
         "{} - Listening on *:{}",
@@ -77,22 +224,35 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
         port
     );
 
+    let connect_options = PgConnectOptions::new()
+        .username(db_user)
+        .password(db_pass)
+        .port(db_port)
+        .database(db_name);
+
+    // sqlx's PgConnectOptions has no separate hostaddr field like libpq, so
+    // there is no way to dial a literal IP while keeping `host` for TLS/SNI.
+    // When --db-hostaddr is set, connect straight to it (skipping DNS) and
+    // keep the original hostname only as application_name, for visibility
+    // in pg_stat_activity; otherwise connect via db_host as before. Note
+    // that with TLS enabled this means cert/SNI verification runs against
+    // the IP, not the hostname, and will fail against a hostname cert.
+    let connect_options = match db_hostaddr {
+        Some(hostaddr) => connect_options
+            .host(&hostaddr.to_string())
+            .application_name(db_host),
+        None => connect_options.host(db_host),
+    };
+
     let pool = PgPoolOptions::new()
         .acquire_timeout(Duration::new(5, 0))
         .idle_timeout(Duration::new(60, 0))
         .max_connections(5)
-        .connect(
-            format!(
-                "postgres://{}:{}@{}/demo",
-                env!("DB_USER"),
-                env!("DB_PASS"),
-                env!("DB_HOST")
-            )
-            .as_ref(),
-        )
+        .connect_with(connect_options)
         .await?;
 
     let db = warp::any().map(move || pool.clone());
+    let registry_filter = warp::any().map(move || registry.clone());
 
     // define the routes to use
     let hello = warp::get().and(log_headers()).and_then(hello);
@@ -101,10 +261,19 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
         .and(db.clone())
         .and_then(query);
     let health = warp::any().and(warp::path("health")).and_then(health);
+    let metrics = warp::get()
+        .and(warp::path("metrics"))
+        .and(registry_filter)
+        .and_then(metrics);
 
     // GET /*
     // ANY /health
-    let routes = health.or(query).or(hello);
+    // GET /metrics
+    let routes = health
+        .or(metrics)
+        .or(query)
+        .or(hello)
+        .recover(handle_rejection);
 
     // listen in both tcp46 falling back to IPv4
     let addr = match IpAddr::from_str("::0") {
@@ -112,46 +281,132 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
         Err(_) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
     };
 
-    // start service
-    warp::serve(routes).run((addr, port)).await;
+    // start service, flushing in-flight batched telemetry on SIGINT/SIGTERM
+    // instead of dropping it when the process is killed mid-export
+    let (_, server) = warp::serve(routes).bind_with_graceful_shutdown((addr, port), shutdown_signal());
+    server.await;
 
     shutdown_tracer_provider();
+    let _ = meter_provider.shutdown();
+    let _ = logger_provider.shutdown();
 
     Ok(())
 }
 
-fn log_headers() -> impl Filter<Extract = (), Error = Infallible> + Copy {
-    warp::header::headers_cloned()
-        .map(|headers: HeaderMap| {
-            let tracer = global::tracer("global_tracer");
-            let mut header_hashmap: HashMap<String, String> = HashMap::new();
-            for (k, v) in headers.iter() {
-                let k = k.as_str().to_owned();
-                let v = String::from_utf8_lossy(v.as_bytes()).into_owned();
-                header_hashmap.entry(k).or_insert(v);
-            }
-            let parent_cx =
-                global::get_text_map_propagator(|propagator| propagator.extract(&header_hashmap));
-            let mut child = tracer
-                .span_builder("log headers")
-                .start_with_context(&tracer, &parent_cx);
-
-            let j = serde_json::to_string(&header_hashmap).unwrap();
-            println!("{}", j);
-            child.end();
-        })
-        .untuple_one()
+// resolves once either ctrl-c or, on unix, SIGTERM is received, so
+// containerized deployments get a chance to flush telemetry before exit
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install ctrl-c handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+// extracts the W3C traceparent (if any) from the inbound headers and hands
+// it on to the handler, so a downstream call can continue the same trace
+// instead of starting a new one
+fn log_headers() -> impl Filter<Extract = (opentelemetry::Context,), Error = Infallible> + Copy {
+    warp::header::headers_cloned().map(|headers: HeaderMap| {
+        let tracer = global::tracer("global_tracer");
+        let mut header_hashmap: HashMap<String, String> = HashMap::new();
+        for (k, v) in headers.iter() {
+            let k = k.as_str().to_owned();
+            let v = String::from_utf8_lossy(v.as_bytes()).into_owned();
+            header_hashmap.entry(k).or_insert(v);
+        }
+        let parent_cx =
+            global::get_text_map_propagator(|propagator| propagator.extract(&header_hashmap));
+        let child = tracer
+            .span_builder("log headers")
+            .start_with_context(&tracer, &parent_cx);
+
+        let j = serde_json::to_string(&header_hashmap).unwrap();
+        {
+            let _guard = parent_cx.with_span(child).attach();
+            info!("{}", j);
+        }
+        parent_cx
+    })
+}
+
+// returned when an upstream call (httpbin, postgres) fails; mapped to a
+// 502 so the real outcome, not just a successful handler return, is what
+// gets counted in `record_request`
+#[derive(Debug)]
+struct UpstreamError;
+
+impl warp::reject::Reject for UpstreamError {}
+
+// maps UpstreamError to the 502 that record_request already assumed it
+// gets, instead of warp's default 500 for an unhandled custom rejection
+async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, Infallible> {
+    let status = if err.find::<UpstreamError>().is_some() {
+        warp::http::StatusCode::BAD_GATEWAY
+    } else {
+        warp::http::StatusCode::INTERNAL_SERVER_ERROR
+    };
+    Ok(warp::reply::with_status(warp::reply(), status))
 }
 
 // GET  /*
-async fn hello() -> Result<impl warp::Reply, warp::Rejection> {
-    let resp = reqwest::get("https://httpbin.org/ip")
-        .await
-        .unwrap()
-        .json::<HashMap<String, String>>()
+async fn hello(parent_cx: opentelemetry::Context) -> Result<impl warp::Reply, warp::Rejection> {
+    let start = Instant::now();
+    let tracer = global::tracer("global_tracer");
+    let child = tracer.start_with_context("call httpbin", &parent_cx);
+    let cx = parent_cx.with_span(child);
+
+    let mut carrier: HashMap<String, String> = HashMap::new();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&cx, &mut carrier));
+    let mut headers = HeaderMap::new();
+    for (k, v) in carrier {
+        if let (Ok(name), Ok(value)) = (
+            reqwest::header::HeaderName::from_bytes(k.as_bytes()),
+            reqwest::header::HeaderValue::from_str(&v),
+        ) {
+            headers.insert(name, value);
+        }
+    }
+
+    let body = reqwest::Client::new()
+        .get("https://httpbin.org/ip")
+        .headers(headers)
+        .send()
         .await
-        .unwrap();
-    let rs = HTML.replace("{}", resp.get("origin").unwrap());
+        .and_then(|resp| resp.error_for_status());
+
+    let body = match body {
+        Ok(resp) => resp.json::<HashMap<String, String>>().await,
+        Err(e) => Err(e),
+    };
+
+    let body = match body {
+        Ok(body) => body,
+        Err(e) => {
+            error!("failed to call httpbin: {}", e);
+            record_request("/", 502, start);
+            return Err(warp::reject::custom(UpstreamError));
+        }
+    };
+
+    let rs = HTML.replace("{}", body.get("origin").map_or("", String::as_str));
+    record_request("/", 200, start);
     Ok(warp::reply::html(rs))
 }
 
@@ -164,6 +419,11 @@ pub struct Bookings {
 
 // GET  /query
 async fn query(db: Pool<Postgres>) -> Result<impl warp::Reply, warp::Rejection> {
+    let start = Instant::now();
+    let tracer = global::tracer("global_tracer");
+    let child = tracer.start("query bookings");
+    let _guard = opentelemetry::Context::current_with_span(child).attach();
+
     let rows = sqlx::query(
         r#"
 SELECT   *
@@ -173,8 +433,16 @@ LIMIT    10
         "#,
     )
     .fetch_all(&db)
-    .await
-    .expect("some error");
+    .await;
+
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("failed to query bookings: {}", e);
+            record_request("/query", 502, start);
+            return Err(warp::reject::custom(UpstreamError));
+        }
+    };
 
     let x: Vec<Bookings> = rows
         .iter()
@@ -185,18 +453,35 @@ LIMIT    10
         })
         .collect();
 
+    info!("query returned {} bookings", x.len());
+    record_request("/query", 200, start);
     Ok(warp::reply::json(&x))
 }
 
+// GET  /metrics
+// render the collected OpenTelemetry metrics in Prometheus text exposition
+// format, for scrapers that pull rather than receive OTLP pushes
+async fn metrics(registry: prometheus::Registry) -> Result<impl warp::Reply, warp::Rejection> {
+    let metric_families = registry.gather();
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder.encode(&metric_families, &mut buffer).unwrap();
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(buffer))
+}
+
 // ANY /health
 // return X-APP header and the commit in the body
 async fn health() -> Result<impl warp::Reply, warp::Rejection> {
+    let start = Instant::now();
     let short_hash = if GIT_COMMIT_HASH.len() > 7 {
         &GIT_COMMIT_HASH[0..7]
     } else {
         ""
     };
-    Ok(Response::builder()
+    let resp = Response::builder()
         .header(
             "X-App",
             format!(
@@ -206,5 +491,7 @@ async fn health() -> Result<impl warp::Reply, warp::Rejection> {
                 short_hash
             ),
         )
-        .body(GIT_COMMIT_HASH))
+        .body(GIT_COMMIT_HASH);
+    record_request("/health", 200, start);
+    Ok(resp)
 }